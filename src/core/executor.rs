@@ -2,14 +2,15 @@
 use std::path::{Path, PathBuf};
 use anyhow::Result;
 use crate::{
-    conf::config, 
-    mount::{magic, overlay}, 
+    conf::config,
+    mount::{hymofs::HymoFs, magic, overlay},
     utils,
     core::planner::MountPlan
 };
 
 pub struct ExecutionResult {
     pub overlay_module_ids: Vec<String>,
+    pub hymofs_module_ids: Vec<String>,
     pub magic_module_ids: Vec<String>,
 }
 
@@ -61,7 +62,33 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
         log::info!("{} modules fell back to Magic Mount.", fallback_ids.len());
     }
 
-    // 2. Execute Magic Mounts
+    // 2. Execute HymoFS Injections
+    let mut final_hymofs_ids = plan.hymofs_module_ids.clone();
+    let mut hymofs_fallback_ids = Vec::new();
+
+    for op in &plan.hymofs_ops {
+        log::info!("Injecting {} [HYMOFS]", op.module_dir.display());
+
+        // inject_directory runs the whole module through a single batched
+        // Transaction: on error it has already unwound anything it applied
+        // before returning, so there are no orphaned rules to clean up here -
+        // the module just needs to be routed to the magic queue instead.
+        if let Err(e) = HymoFs::inject_directory(&op.target_base, &op.module_dir) {
+            log::warn!("HymoFS injection failed for {}: {}. Triggering fallback.", op.module_dir.display(), e);
+
+            magic_queue.push(op.module_dir.clone());
+            if let Some(id) = op.module_dir.file_name() {
+                hymofs_fallback_ids.push(id.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    if !hymofs_fallback_ids.is_empty() {
+        final_hymofs_ids.retain(|id| !hymofs_fallback_ids.contains(id));
+        log::info!("{} modules fell back to Magic Mount.", hymofs_fallback_ids.len());
+    }
+
+    // 3. Execute Magic Mounts
     // Deduplicate queue first (a module might span multiple partitions, 
     // failure in one partition shouldn't add it twice)
     magic_queue.sort();
@@ -105,11 +132,14 @@ pub fn execute(plan: &MountPlan, config: &config::Config) -> Result<ExecutionRes
     // Final cleanup of ID lists
     final_overlay_ids.sort();
     final_overlay_ids.dedup();
+    final_hymofs_ids.sort();
+    final_hymofs_ids.dedup();
     final_magic_ids.sort();
     final_magic_ids.dedup();
 
     Ok(ExecutionResult {
         overlay_module_ids: final_overlay_ids,
+        hymofs_module_ids: final_hymofs_ids,
         magic_module_ids: final_magic_ids,
     })
 }
@@ -2,14 +2,22 @@ use std::fs::{self, File};
 use std::io::Write;
 use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use log::{debug, warn};
+use rayon::prelude::*;
 use walkdir::WalkDir;
 
+use self::docket::{Docket, RuleRecord};
+
 const HYMO_CTL: &str = "/proc/hymo_ctl";
 const EXPECTED_PROTOCOL_VERSION: i32 = 3;
 
+/// Bounded retry count for reading the control node.
+const VERSION_READ_ATTEMPTS: u32 = 5;
+const VERSION_READ_BACKOFF: Duration = Duration::from_millis(20);
+
 #[derive(Debug, PartialEq)]
 pub enum HymoFsStatus {
     Available,
@@ -34,12 +42,33 @@ impl HymoFs {
         None
     }
 
+    /// Re-reads the control node up to [`VERSION_READ_ATTEMPTS`] times. The node
+    /// can briefly read back empty or truncated while the kernel module is being
+    /// (re)loaded concurrently, which would otherwise be misclassified as
+    /// `NotPresent`; only give up once the version is still unparseable after the
+    /// whole retry budget is spent.
+    fn read_protocol_version_with_retry() -> Option<i32> {
+        for attempt in 1..=VERSION_READ_ATTEMPTS {
+            if let Some(version) = Self::get_protocol_version() {
+                return Some(version);
+            }
+            if attempt < VERSION_READ_ATTEMPTS {
+                debug!(
+                    "HymoFS control node version unreadable (attempt {}/{}), retrying",
+                    attempt, VERSION_READ_ATTEMPTS
+                );
+                std::thread::sleep(VERSION_READ_BACKOFF);
+            }
+        }
+        None
+    }
+
     pub fn check_status() -> HymoFsStatus {
         if !Path::new(HYMO_CTL).exists() {
             return HymoFsStatus::NotPresent;
         }
 
-        let kernel_version = match Self::get_protocol_version() {
+        let kernel_version = match Self::read_protocol_version_with_retry() {
             Some(v) => v,
             None => return HymoFsStatus::NotPresent,
         };
@@ -82,7 +111,6 @@ impl HymoFs {
         Self::send_cmd(&cmd)
     }
 
-    #[allow(dead_code)]
     pub fn delete_rule(src: &Path) -> Result<()> {
         Self::send_cmd(&format!("delete {}", src.display()))
     }
@@ -95,33 +123,172 @@ impl HymoFs {
         Self::send_cmd(&format!("inject {}", dir.display()))
     }
 
+    /// Walks `module_dir` and applies its rules against `target_base`, reusing the
+    /// on-disk docket to skip rules that are already in effect from a previous run.
+    ///
+    /// Unlike the old `clear()` + full re-inject approach, this computes the set
+    /// difference between the docket's last-known state and the freshly-walked
+    /// tree, and only emits `add`/`delete`/`hide`/`inject` for entries that
+    /// actually changed - directory `inject`s are tracked in the docket too,
+    /// not just file rules.
+    ///
+    /// The walk itself is collected up front, bucketed by depth, and the expensive
+    /// per-entry work (metadata/stat, path stripping) is done in parallel with
+    /// rayon within each depth bucket. Buckets are still processed in increasing
+    /// depth order so a directory's `inject` is always sent before rules for its
+    /// children, even though siblings within a bucket are computed concurrently.
+    ///
+    /// Every `add`/`hide` issued is tracked in a batched [`Transaction`]; if the
+    /// walk or any command fails partway through, the transaction unwinds on drop
+    /// so the module doesn't end up half-injected. Since the transaction is
+    /// batched, nothing actually reaches the control node until it commits, at
+    /// which point the whole module is applied in a single write.
     pub fn inject_directory(target_base: &Path, module_dir: &Path) -> Result<()> {
         if !module_dir.exists() || !module_dir.is_dir() {
             return Ok(());
         }
 
-        Self::inject_dir(target_base)?;
+        let mut tx = Self::begin_transaction();
 
-        for entry in WalkDir::new(module_dir).min_depth(1) {
-            let entry = entry?;
-            let current_path = entry.path();
-            
-            let relative_path = current_path.strip_prefix(module_dir)?;
-            let target_path = target_base.join(relative_path);
-            let file_type = entry.file_type();
-
-            if file_type.is_file() {
-                Self::add_rule(&target_path, current_path, Some(8))?;
-            } else if file_type.is_symlink() {
-                Self::add_rule(&target_path, current_path, Some(10))?;
-            } else if file_type.is_char_device() {
-                let metadata = entry.metadata()?;
-                if metadata.rdev() == 0 {
-                    Self::hide_path(&target_path)?;
+        // Docket is keyed by module, not by target path: two modules may inject
+        // under overlapping target trees, but each must only ever diff (and
+        // potentially delete) its own previously recorded rules.
+        let module_id = module_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| module_dir.display().to_string());
+        let mut docket = Docket::load(&module_id);
+        let mut fresh = std::collections::HashMap::new();
+
+        // A directory whose docket record is still `Dir` is already injected
+        // and unchanged, so re-sending `inject` for it buys nothing.
+        let is_fresh_dir = |docket: &Docket, path: &Path| {
+            !matches!(docket.get(path), Some(RuleRecord::Dir))
+        };
+
+        if is_fresh_dir(&docket, target_base) {
+            tx.inject_dir(target_base)?;
+        }
+        fresh.insert(target_base.to_path_buf(), RuleRecord::Dir);
+
+        let entries = WalkDir::new(module_dir)
+            .min_depth(1)
+            .into_iter()
+            .collect::<walkdir::Result<Vec<_>>>()?;
+
+        let max_depth = entries.iter().map(|e| e.depth()).max().unwrap_or(0);
+        let mut buckets: Vec<Vec<&walkdir::DirEntry>> = vec![Vec::new(); max_depth + 1];
+        for entry in &entries {
+            buckets[entry.depth()].push(entry);
+        }
+
+        for bucket in buckets {
+            let (dirs, rest): (Vec<_>, Vec<_>) =
+                bucket.into_iter().partition(|e| e.file_type().is_dir());
+
+            // Directory injects must precede rules for their children, so they
+            // stay on the serial path; diffed against the docket just like
+            // `target_base` above.
+            for entry in &dirs {
+                let relative_path = entry.path().strip_prefix(module_dir)?;
+                let target_path = target_base.join(relative_path);
+                if is_fresh_dir(&docket, &target_path) {
+                    tx.inject_dir(&target_path)?;
                 }
-            } else if file_type.is_dir() {
-                Self::inject_dir(&target_path)?;
+                fresh.insert(target_path, RuleRecord::Dir);
             }
+
+            let computed = rest
+                .par_iter()
+                .map(|entry| -> Result<Option<(std::path::PathBuf, RuleRecord)>> {
+                    let current_path = entry.path();
+                    let relative_path = current_path.strip_prefix(module_dir)?;
+                    let target_path = target_base.join(relative_path);
+                    let file_type = entry.file_type();
+
+                    if file_type.is_file() {
+                        let metadata = entry.metadata()?;
+                        Ok(Some((
+                            target_path,
+                            RuleRecord::Add {
+                                src: current_path.to_path_buf(),
+                                file_type: 8,
+                                size: metadata.len(),
+                                mtime_sec: metadata.mtime(),
+                                mtime_nsec: metadata.mtime_nsec() as u32,
+                            },
+                        )))
+                    } else if file_type.is_symlink() {
+                        let metadata = entry.metadata()?;
+                        Ok(Some((
+                            target_path,
+                            RuleRecord::Add {
+                                src: current_path.to_path_buf(),
+                                file_type: 10,
+                                size: metadata.len(),
+                                mtime_sec: metadata.mtime(),
+                                mtime_nsec: metadata.mtime_nsec() as u32,
+                            },
+                        )))
+                    } else if file_type.is_char_device() {
+                        let metadata = entry.metadata()?;
+                        if metadata.rdev() == 0 {
+                            Ok(Some((target_path, RuleRecord::Hide)))
+                        } else {
+                            Ok(None)
+                        }
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            for (target_path, record) in computed.into_iter().flatten() {
+                fresh.insert(target_path, record);
+            }
+        }
+
+        let diff = docket.diff(&fresh);
+        debug!(
+            "HymoFS docket diff for {}: {} to add, {} to delete",
+            module_dir.display(),
+            diff.to_add.len(),
+            diff.to_delete.len()
+        );
+
+        for target_path in &diff.to_delete {
+            // There's no inverse "uninject" command: a directory that dropped
+            // out of the module tree is simply never injected again, rather
+            // than being torn down.
+            if matches!(docket.get(target_path), Some(RuleRecord::Dir)) {
+                continue;
+            }
+            tx.delete_rule(target_path)?;
+        }
+        for (target_path, record) in &diff.to_add {
+            match record {
+                RuleRecord::Add { src, file_type, .. } => {
+                    tx.add_rule(target_path, src, Some(*file_type))?;
+                }
+                RuleRecord::Hide => {
+                    tx.hide_path(target_path)?;
+                }
+                // Already sent inline during the walk above, in the order the
+                // directory tree requires; nothing left to do here.
+                RuleRecord::Dir => {}
+            }
+        }
+
+        // Commit before persisting the docket: the transaction is batched, so
+        // nothing actually reaches the control node until `commit` flushes it.
+        // Writing the docket first would claim these rules are applied even if
+        // the flush then failed.
+        tx.commit()?;
+        // The rules are applied once committed, so a failed docket write is not
+        // reported as an injection failure; the next run diffs against the old
+        // docket instead.
+        if let Err(e) = docket.persist(fresh, &diff) {
+            warn!("HymoFS docket persist failed for {}: {:#}", module_dir.display(), e);
         }
         Ok(())
     }
@@ -140,4 +307,464 @@ impl HymoFs {
         }
         Ok(())
     }
+
+    /// Starts a [`Transaction`] that buffers every `add`/`hide`/`delete`/`inject`
+    /// issued through it in a [`Batch`], so a whole module is applied in a
+    /// single write on `commit`. If dropped without committing, nothing has
+    /// reached the control node yet, so the buffer is simply discarded.
+    pub fn begin_transaction() -> Transaction {
+        Transaction {
+            committed: false,
+            batch: Self::begin_batch(),
+        }
+    }
+
+    /// Starts a [`Batch`] that buffers `add`/`hide`/`inject` commands and flushes
+    /// them as newline-delimited lines in a single `File::create` + write, instead
+    /// of paying one `open()` per rule. Flushes automatically on drop as well as
+    /// on [`Batch::commit`], so buffered commands are never silently lost.
+    pub fn begin_batch() -> Batch {
+        Batch { lines: Vec::new() }
+    }
+}
+
+/// Guards a single module's HymoFS rule application. Every `add`/`hide`/`delete`/
+/// `inject` issued through the transaction is buffered in a [`Batch`] rather
+/// than sent immediately, so nothing actually reaches the control node until
+/// [`Transaction::commit`] flushes it in a single write. If the transaction is
+/// dropped without committing - because the caller hit an error partway
+/// through a module - the buffer is simply discarded, so the module is never
+/// left half-injected.
+pub struct Transaction {
+    committed: bool,
+    batch: Batch,
+}
+
+impl Transaction {
+    pub fn add_rule(&mut self, src: &Path, target: &Path, file_type: Option<u32>) -> Result<()> {
+        self.batch.add_rule(src, target, file_type);
+        Ok(())
+    }
+
+    pub fn hide_path(&mut self, path: &Path) -> Result<()> {
+        self.batch.hide_path(path);
+        Ok(())
+    }
+
+    pub fn delete_rule(&mut self, src: &Path) -> Result<()> {
+        self.batch.delete_rule(src);
+        Ok(())
+    }
+
+    pub fn inject_dir(&mut self, dir: &Path) -> Result<()> {
+        self.batch.inject_dir(dir);
+        Ok(())
+    }
+
+    /// Marks the transaction successful, flushing the buffered commands to the
+    /// control node in a single write.
+    pub fn commit(mut self) -> Result<()> {
+        self.committed = true;
+        std::mem::take(&mut self.batch).commit()
+    }
+
+    /// Explicitly discards the transaction's buffered commands without sending
+    /// them.
+    pub fn rollback(mut self) {
+        self.unwind();
+    }
+
+    fn unwind(&mut self) {
+        std::mem::take(&mut self.batch).discard();
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.unwind();
+        }
+    }
+}
+
+/// Buffers `add`/`delete`/`hide`/`inject` commands and flushes them as
+/// newline-delimited lines in a single `File::create` + write, since the control
+/// protocol is already line-oriented. Flushes on [`Batch::commit`] or, as a
+/// safety net against forgetting to commit, on drop.
+#[derive(Default)]
+pub struct Batch {
+    lines: Vec<String>,
+}
+
+impl Batch {
+    pub fn add_rule(&mut self, src: &Path, target: &Path, file_type: Option<u32>) {
+        let type_str = file_type.unwrap_or(0).to_string();
+        self.lines
+            .push(format!("add {} {} {}", src.display(), target.display(), type_str));
+    }
+
+    pub fn delete_rule(&mut self, src: &Path) {
+        self.lines.push(format!("delete {}", src.display()));
+    }
+
+    pub fn hide_path(&mut self, path: &Path) {
+        self.lines.push(format!("hide {}", path.display()));
+    }
+
+    pub fn inject_dir(&mut self, dir: &Path) {
+        self.lines.push(format!("inject {}", dir.display()));
+    }
+
+    /// Flushes every buffered command in one write.
+    pub fn commit(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Drops the buffer without ever writing it, used when a transaction unwinds
+    /// before anything was sent to the kernel.
+    pub fn discard(mut self) {
+        self.lines.clear();
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let mut file =
+            File::create(HYMO_CTL).with_context(|| format!("Failed to open {}", HYMO_CTL))?;
+        let mut buf = self.lines.join("\n");
+        buf.push('\n');
+        file.write_all(buf.as_bytes())?;
+        debug!("HymoFS Batch: flushed {} commands in one write", self.lines.len());
+        self.lines.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("HymoFS batch flush on drop failed: {:#}", e);
+        }
+    }
+}
+
+/// On-disk record of the HymoFS rules currently applied, used to diff against a
+/// freshly-walked module tree instead of re-issuing every rule on each boot.
+///
+/// The docket is an append-mostly log, borrowing the heuristic Mercurial's
+/// dirstate-v2 uses for its own data file: new/changed records are appended as
+/// they occur, and the file is only rewritten from scratch once the fraction of
+/// stale (deleted or superseded) records on disk crosses ~0.5. This keeps restart
+/// cost proportional to the delta since the last run rather than to the total
+/// module size.
+mod docket {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use log::warn;
+
+    use super::EXPECTED_PROTOCOL_VERSION;
+
+    const DOCKET_DIR: &str = "/data/adb/hymo/dockets";
+    const DOCKET_MARKER: &str = "HYMODOCK1";
+    const REWRITE_THRESHOLD: f64 = 0.5;
+
+    /// One docket file per module, so diffing module B's fresh tree against the
+    /// docket never sees (and so never deletes) module A's rules.
+    fn docket_path(module_id: &str) -> PathBuf {
+        Path::new(DOCKET_DIR).join(format!("{}.dock", module_id))
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub enum RuleRecord {
+        Add {
+            src: PathBuf,
+            file_type: u32,
+            size: u64,
+            mtime_sec: i64,
+            mtime_nsec: u32,
+        },
+        Hide,
+        Dir,
+    }
+
+    #[derive(Default)]
+    pub struct DocketDiff {
+        pub to_add: Vec<(PathBuf, RuleRecord)>,
+        pub to_delete: Vec<PathBuf>,
+        /// Number of `to_add` entries that supersede an existing record (as
+        /// opposed to being brand new), tracked for the rewrite-threshold heuristic.
+        superseded: usize,
+    }
+
+    pub struct Docket {
+        path: PathBuf,
+        entries: HashMap<PathBuf, RuleRecord>,
+        /// Number of entry lines currently on disk (including stale ones), used to
+        /// decide when the stale fraction warrants a full rewrite.
+        lines_on_disk: usize,
+        /// Wall-clock second at which the docket was last written, used to detect
+        /// the Mercurial dirstate "ambiguous mtime" case: a file whose mtime equals
+        /// that second could have been rewritten again within the same second,
+        /// which a second-granularity stat can't distinguish.
+        last_written_secs: Option<i64>,
+        /// Whether a valid `DOCKET_MARKER`/`PROTOCOL` header is currently on
+        /// disk at `path`. False for a missing file and for one `load` had to
+        /// reject, so `persist` knows it must rewrite rather than append -
+        /// appending onto a rejected or absent header would leave the file
+        /// permanently unloadable (or, for a missing file, headerless).
+        header_valid: bool,
+    }
+
+    impl Docket {
+        fn empty(path: PathBuf) -> Self {
+            Docket {
+                path,
+                entries: HashMap::new(),
+                lines_on_disk: 0,
+                last_written_secs: None,
+                header_valid: false,
+            }
+        }
+
+        /// Loads the module's docket from disk, tolerating a missing file (first
+        /// run) and skipping malformed lines rather than failing the whole load.
+        pub fn load(module_id: &str) -> Self {
+            let path = docket_path(module_id);
+            let content = match fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(_) => return Docket::empty(path),
+            };
+
+            let mut lines = content.lines();
+            match lines.next() {
+                Some(marker) if marker == DOCKET_MARKER => {}
+                _ => {
+                    warn!("HymoFS docket missing or unrecognised format marker, ignoring");
+                    return Docket::empty(path);
+                }
+            }
+
+            match lines.next().and_then(|l| l.strip_prefix("PROTOCOL ")) {
+                Some(v) if v.parse::<i32>() == Ok(EXPECTED_PROTOCOL_VERSION) => {}
+                _ => {
+                    warn!("HymoFS docket protocol version mismatch, discarding");
+                    return Docket::empty(path);
+                }
+            }
+
+            let mut entries = HashMap::new();
+            let mut lines_on_disk = 0;
+            let mut last_written_secs = None;
+            for line in lines {
+                lines_on_disk += 1;
+                if let Some(rest) = line.strip_prefix("ADD ") {
+                    let mut parts = rest.splitn(5, '\t');
+                    if let (Some(target), Some(src), Some(ft), Some(size), Some(mtime)) = (
+                        parts.next(),
+                        parts.next(),
+                        parts.next(),
+                        parts.next(),
+                        parts.next(),
+                    ) {
+                        let parsed = ft
+                            .parse::<u32>()
+                            .ok()
+                            .zip(size.parse::<u64>().ok())
+                            .zip(parse_mtime(mtime));
+                        if let Some(((file_type, size), (mtime_sec, mtime_nsec))) = parsed {
+                            entries.insert(
+                                PathBuf::from(target),
+                                RuleRecord::Add {
+                                    src: PathBuf::from(src),
+                                    file_type,
+                                    size,
+                                    mtime_sec,
+                                    mtime_nsec,
+                                },
+                            );
+                            continue;
+                        }
+                    }
+                    warn!("HymoFS docket: malformed ADD line, skipping");
+                } else if let Some(target) = line.strip_prefix("HIDE ") {
+                    entries.insert(PathBuf::from(target), RuleRecord::Hide);
+                } else if let Some(target) = line.strip_prefix("DIR ") {
+                    entries.insert(PathBuf::from(target), RuleRecord::Dir);
+                } else if let Some(target) = line.strip_prefix("DEL ") {
+                    entries.remove(Path::new(target));
+                } else if let Some(secs) = line.strip_prefix("WRITTEN ") {
+                    if let Ok(secs) = secs.parse::<i64>() {
+                        last_written_secs = Some(secs);
+                    }
+                } else {
+                    warn!("HymoFS docket: unrecognised line, skipping");
+                }
+            }
+
+            Docket {
+                path,
+                entries,
+                lines_on_disk,
+                last_written_secs,
+                header_valid: true,
+            }
+        }
+
+        /// Looks up the record the docket last recorded for `target`, if any.
+        pub fn get(&self, target: &Path) -> Option<&RuleRecord> {
+            self.entries.get(target)
+        }
+
+        /// Computes the set difference between the docket's last-known state and
+        /// the freshly-walked `fresh` rule set. A file whose stored mtime lands on
+        /// the same wall-clock second the docket was last written is always
+        /// treated as changed, since that second alone can't rule out a write that
+        /// happened after the docket was persisted.
+        pub fn diff(&self, fresh: &HashMap<PathBuf, RuleRecord>) -> DocketDiff {
+            let mut diff = DocketDiff::default();
+
+            for (target, record) in fresh {
+                let ambiguous = matches!(
+                    (record, self.last_written_secs),
+                    (RuleRecord::Add { mtime_sec, .. }, Some(written)) if *mtime_sec == written
+                );
+
+                match self.entries.get(target) {
+                    Some(existing) if existing == record && !ambiguous => {}
+                    Some(_) => {
+                        diff.to_add.push((target.clone(), record.clone()));
+                        diff.superseded += 1;
+                    }
+                    None => diff.to_add.push((target.clone(), record.clone())),
+                }
+            }
+            for target in self.entries.keys() {
+                if !fresh.contains_key(target) {
+                    diff.to_delete.push(target.clone());
+                }
+            }
+
+            diff
+        }
+
+        /// Persists `fresh` as the new docket state. Appends only the changed
+        /// records when the stale fraction on disk is still small; rewrites the
+        /// whole file from scratch once it crosses `REWRITE_THRESHOLD`.
+        pub fn persist(
+            &mut self,
+            fresh: HashMap<PathBuf, RuleRecord>,
+            diff: &DocketDiff,
+        ) -> anyhow::Result<()> {
+            let appended = diff.to_add.len() + diff.to_delete.len();
+            let projected_lines = self.lines_on_disk + appended;
+            // Lines already on disk that no longer describe a live record, plus
+            // the records this run deletes or supersedes.
+            let stale = self.lines_on_disk.saturating_sub(self.entries.len())
+                + diff.to_delete.len()
+                + diff.superseded;
+
+            let stale_fraction = if projected_lines == 0 {
+                0.0
+            } else {
+                stale as f64 / projected_lines as f64
+            };
+
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let now_secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+
+            if !self.header_valid || stale_fraction > REWRITE_THRESHOLD {
+                self.rewrite(&fresh, now_secs)?;
+                self.header_valid = true;
+            } else {
+                self.append(diff, now_secs)?;
+            }
+
+            self.entries = fresh;
+            self.last_written_secs = Some(now_secs);
+            Ok(())
+        }
+
+        fn rewrite(&mut self, fresh: &HashMap<PathBuf, RuleRecord>, now_secs: i64) -> anyhow::Result<()> {
+            let mut file = fs::File::create(&self.path)?;
+            writeln!(file, "{}", DOCKET_MARKER)?;
+            writeln!(file, "PROTOCOL {}", EXPECTED_PROTOCOL_VERSION)?;
+            for (target, record) in fresh {
+                write_record(&mut file, target, record)?;
+            }
+            writeln!(file, "WRITTEN {}", now_secs)?;
+            self.lines_on_disk = fresh.len() + 1;
+            Ok(())
+        }
+
+        /// Only ever called once `persist` has confirmed a valid header is
+        /// already on disk (freshly `rewrite`-n or loaded from a prior valid
+        /// docket), so this never needs to write the header itself.
+        fn append(&mut self, diff: &DocketDiff, now_secs: i64) -> anyhow::Result<()> {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+
+            for target in &diff.to_delete {
+                writeln!(file, "DEL {}", target.display())?;
+            }
+            for (target, record) in &diff.to_add {
+                write_record(&mut file, target, record)?;
+            }
+            writeln!(file, "WRITTEN {}", now_secs)?;
+
+            self.lines_on_disk += diff.to_add.len() + diff.to_delete.len() + 1;
+            Ok(())
+        }
+    }
+
+    fn parse_mtime(field: &str) -> Option<(i64, u32)> {
+        let (sec, nsec) = field.split_once('.')?;
+        Some((sec.parse().ok()?, nsec.parse().ok()?))
+    }
+
+    fn write_record(
+        file: &mut fs::File,
+        target: &Path,
+        record: &RuleRecord,
+    ) -> anyhow::Result<()> {
+        match record {
+            RuleRecord::Add {
+                src,
+                file_type,
+                size,
+                mtime_sec,
+                mtime_nsec,
+            } => {
+                writeln!(
+                    file,
+                    "ADD {}\t{}\t{}\t{}\t{}.{}",
+                    target.display(),
+                    src.display(),
+                    file_type,
+                    size,
+                    mtime_sec,
+                    mtime_nsec
+                )?;
+            }
+            RuleRecord::Hide => {
+                writeln!(file, "HIDE {}", target.display())?;
+            }
+            RuleRecord::Dir => {
+                writeln!(file, "DIR {}", target.display())?;
+            }
+        }
+        Ok(())
+    }
 }